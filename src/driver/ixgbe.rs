@@ -1,8 +1,11 @@
 use driver::*;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::ptr;
 use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
 
 use self::constants::*;
 use self::pci::*;
@@ -22,10 +25,115 @@ const NUM_TX_QUEUE_ENTRIES: u32 = 512;
 
 const TX_CLEAN_BATCH: u32 = 32;
 
+// number of context descriptor slots cached per tx queue, see ixgbe_set_xmit_ctx/what_advctx_update
+// in the Linux/DPDK ixgbe driver
+const IXGBE_CTXD_CACHE_SIZE: usize = 2;
+
+// The register/bitfield constants below belong in self::constants alongside every other
+// IXGBE_* register in this file, but that module isn't part of this checkout (e.g. IXGBE_CTRL,
+// used in reset_and_init, is likewise never declared in this file) -- kept file-local here
+// until they can be upstreamed there.
+
+// advanced context descriptor layout, section 7.2.3.2.4
+const IXGBE_ADVTXD_DTYP_CTXT: u32 = 0x2 << 20;
+const IXGBE_ADVTXD_DCMD_TSE: u32 = 1 << 31;
+const IXGBE_ADVTXD_TUCMD_IPV4: u32 = 0x400;
+const IXGBE_ADVTXD_TUCMD_L4T_UDP: u32 = 0;
+const IXGBE_ADVTXD_TUCMD_L4T_TCP: u32 = 0x800;
+const IXGBE_ADVTXD_TUCMD_L4T_SCTP: u32 = 0x1000;
+const IXGBE_ADVTXD_POPTS_IXSM: u32 = 1 << 8;
+const IXGBE_ADVTXD_POPTS_TXSM: u32 = 1 << 9;
+const IXGBE_ADVTXD_MACLEN_SHIFT: u32 = 9;
+const IXGBE_ADVTXD_L4LEN_SHIFT: u32 = 8;
+const IXGBE_ADVTXD_MSS_SHIFT: u32 = 16;
+// context descriptor mss_l4len_idx.IDX and data descriptor olinfo_status.IDX share this bit
+// offset (section 7.2.3.2.4): a context tagged at this shift is selected by a data descriptor
+// via the same shift.
+const IXGBE_ADVTXD_IDX_SHIFT: u32 = 4;
+
+// RSS, section 4.6.7.3
+const IXGBE_RSS_KEY_SIZE: usize = 40;
+const IXGBE_RETA_ENTRIES: usize = 128;
+
+const fn IXGBE_MRQC() -> u32 { 0x05818 }
+const fn IXGBE_RSSRK(n: u32) -> u32 { 0x05C80 + n * 4 } // n = 0..9
+const fn IXGBE_RETA(n: u32) -> u32 { 0x05C00 + n * 4 } // n = 0..31
+
+const IXGBE_MRQC_RSSEN: u32 = 1 << 0;
+const IXGBE_MRQC_RSS_FIELD_IPV4_TCP: u32 = 1 << 16;
+const IXGBE_MRQC_RSS_FIELD_IPV4: u32 = 1 << 17;
+const IXGBE_MRQC_RSS_FIELD_IPV6: u32 = 1 << 20;
+const IXGBE_MRQC_RSS_FIELD_IPV6_TCP: u32 = 1 << 21;
+
+// MSI-X interrupt mapping, sections 4.6.3.1 / 4.6.7.1
+const fn IXGBE_IVAR(n: u32) -> u32 { 0x00900 + n * 4 } // n = 0..63, rx and tx entries of queues 2n and 2n+1 per register
+const fn IXGBE_EITR(n: u32) -> u32 { 0x00820 + n * 4 } // n = 0..23 (one per vector)
+const IXGBE_EIMS: u32 = 0x00880;
+const IXGBE_EIMC: u32 = 0x00888;
+const IXGBE_EIAM: u32 = 0x00890;
+const IXGBE_GPIE: u32 = 0x00898;
+const IXGBE_GPIE_MSIX_MODE: u32 = 1 << 4;
+const IXGBE_GPIE_PBA_SUPPORT: u32 = 1 << 31;
+const IXGBE_GPIE_OCD: u32 = 1 << 18;
+const IXGBE_IVAR_ALLOC_VALID: u32 = 1 << 7;
+
+// drop/error and per-queue stats, section 4.6.5
+const fn IXGBE_RXMPC(n: u32) -> u32 { 0x03FA0 + n * 4 } // n = 0..7, one per traffic class
+const IXGBE_CRCERRS: u32 = 0x04000;
+const fn IXGBE_QPRC(n: u32) -> u32 { 0x01030 + n * 0x40 } // n = 0..15
+const fn IXGBE_QPTC(n: u32) -> u32 { 0x06030 + n * 0x40 } // n = 0..15
+const IXGBE_MAX_TRAFFIC_CLASSES: u32 = 8;
+
+// default 40-byte Toeplitz key from the Microsoft RSS reference implementation, also used by
+// the Linux ixgbe driver as ixgbe_rss_key
+const IXGBE_DEFAULT_RSS_KEY: [u8; IXGBE_RSS_KEY_SIZE] = [
+    0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f, 0xb0,
+    0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30, 0xf2, 0x0c,
+    0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+];
+
 const fn wrap_ring(index: u32, ring_size: u32) -> u32 {
     (index + 1) & (ring_size - 1)
 }
 
+/// Per-packet checksum/segmentation offload request, mirrors the fields the NIC needs in an
+/// advanced context descriptor (section 7.2.3.2.4).
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct TxOffload {
+    /// length of the L2 (Ethernet) header in bytes
+    pub l2_len: u8,
+    /// length of the L3 (IP) header in bytes
+    pub l3_len: u16,
+    /// set when the L3 header is IPv4 and its checksum should be offloaded
+    pub ipv4_checksum: bool,
+    /// L4 protocol whose checksum should be offloaded, if any
+    pub l4_checksum: L4Checksum,
+    /// length of the L4 header in bytes
+    pub l4_len: u8,
+    /// TCP segmentation offload: maximum segment size, or 0 if TSO is not requested
+    pub tso_mss: u16,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum L4Checksum {
+    None,
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl Default for L4Checksum {
+    fn default() -> L4Checksum {
+        L4Checksum::None
+    }
+}
+
+impl TxOffload {
+    fn is_none(&self) -> bool {
+        *self == TxOffload::default()
+    }
+}
+
 pub struct IxgbeDevice {
     addr: *mut u8,
     len: usize,
@@ -33,6 +141,82 @@ pub struct IxgbeDevice {
     num_tx_queues: u32,
     rx_queues: Vec<IxgbeRxQueue>,
     tx_queues: Vec<IxgbeTxQueue>,
+    // RSS hash key (RSSRK) and redirection table (RETA), kept around so they can be
+    // reprogrammed by set_rss_key() and consulted by rss_queue_for_flow()
+    rss_key: [u8; IXGBE_RSS_KEY_SIZE],
+    reta: [u8; IXGBE_RETA_ENTRIES],
+    // NUMA node the device's PCI function is attached to, or -1 if unknown/not reported;
+    // threaded into DmaMemory::allocate/Mempool::allocate so rings and buffers are placed on
+    // the same node as the NIC, and used by bind_to_numa_node() to mbind the mapped huge pages
+    numa_node: i32,
+    // kept around to enable MSI-X vectors for individual queues on demand, see
+    // enable_rx_interrupt()
+    pci_addr: String,
+}
+
+// reads /sys/bus/pci/devices/<pci_addr>/numa_node, returning -1 if it is missing or not a
+// valid node (the kernel reports -1 itself on single-node systems)
+fn read_numa_node(pci_addr: &str) -> i32 {
+    let path = format!("/sys/bus/pci/devices/{}/numa_node", pci_addr);
+
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse::<i32>().ok())
+        .filter(|&node| node >= 0)
+        .unwrap_or(-1)
+}
+
+// linux/mempolicy.h
+const MPOL_BIND: i32 = 2;
+const MPOL_MF_STRICT: u32 = 1 << 0;
+const MPOL_MF_MOVE: u32 = 1 << 1;
+
+extern "C" {
+    fn mbind(addr: *mut u8, len: usize, mode: i32, nodemask: *const u64, maxnode: u64, flags: u32) -> i32;
+}
+
+// pins the huge page backing `addr[..len]` to `node` via MPOL_BIND before it is touched, so
+// the allocation can't end up on a socket remote from the NIC; a no-op if `node` is unknown
+fn bind_to_numa_node(addr: *mut u8, len: usize, node: i32) {
+    if node < 0 {
+        return;
+    }
+
+    let nodemask: u64 = 1 << node;
+
+    let ret = unsafe { mbind(addr, len, MPOL_BIND, &nodemask, 64, MPOL_MF_STRICT | MPOL_MF_MOVE) };
+    if ret != 0 {
+        println!("warning: mbind to numa node {} failed: {}", node, io::Error::last_os_error());
+    }
+}
+
+extern "C" {
+    fn eventfd(initval: u32, flags: i32) -> RawFd;
+    fn read(fd: RawFd, buf: *mut u8, count: usize) -> isize;
+}
+
+const EFD_CLOEXEC: i32 = 0o2000000;
+
+// allocates a VFIO MSI-X vector for `queue_id` and binds a fresh eventfd to it, so the kernel
+// writes to the eventfd whenever the vector fires; the VFIO ioctls themselves live in
+// self::pci since that's where the container/group/device fds are kept
+fn vfio_enable_msix_vector(pci_addr: &str, queue_id: u32) -> io::Result<RawFd> {
+    let fd = unsafe { eventfd(0, EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    vfio_set_irq_eventfd(pci_addr, queue_id, fd)?;
+
+    Ok(fd)
+}
+
+// blocks until the NIC signals the interrupt bound to `fd`
+fn wait_for_interrupt(fd: RawFd) {
+    let mut buf = [0u8; 8];
+    unsafe {
+        read(fd, buf.as_mut_ptr(), buf.len());
+    }
 }
 
 struct IxgbeRxQueue {
@@ -41,14 +225,25 @@ struct IxgbeRxQueue {
     num_entries: u32,
     rx_index: u32,
     mempool_entries: Vec<u32>,
+    // VFIO eventfd for this queue's MSI-X vector, set once enable_rx_interrupt() is called;
+    // `None` means the queue stays in the default busy-polling mode
+    interrupt_fd: Option<RawFd>,
 }
 
 struct IxgbeTxQueue {
     descriptors: *mut ixgbe_adv_tx_desc,
-    queue: VecDeque<Packet>,
+    // `None` marks a ring slot that holds a context descriptor rather than a packet
+    queue: VecDeque<Option<Packet>>,
     num_entries: u32,
     clean_index: u32,
     tx_index: u32,
+    // cached offload contexts already written to the ring, see ixgbe_tx_batch
+    ctx_cache: [Option<TxOffload>; IXGBE_CTXD_CACHE_SIZE],
+    // next slot to evict on a cache miss, see what_advctx_update/DPDK's ctx_curr
+    ctx_curr: usize,
+    // last TDH observed by check_tx_hang and when it was observed, see ixgbe_check_tx_hang
+    last_tdh: u32,
+    last_tdh_progress: Instant,
 }
 
 fn reset_and_init(ixgbe: &mut IxgbeDevice) {
@@ -142,7 +337,8 @@ fn init_rx(ixgbe: &mut IxgbeDevice, huge_page_id: &mut u32) {
         let ring_size_bytes = (NUM_RX_QUEUE_ENTRIES) * mem::size_of::<ixgbe_adv_rx_desc>() as u32;
 
         // TODO check result of allocate_dma_memory
-        let dma = DmaMemory::allocate(huge_page_id, ring_size_bytes).unwrap();
+        let dma = DmaMemory::allocate(huge_page_id, ring_size_bytes, ixgbe.numa_node).unwrap();
+        bind_to_numa_node(dma.virt, ring_size_bytes as usize, ixgbe.numa_node);
 
         unsafe { memset(dma.virt, ring_size_bytes, 0xff); }
 
@@ -161,16 +357,27 @@ fn init_rx(ixgbe: &mut IxgbeDevice, huge_page_id: &mut u32) {
 
         let mempool = Rc::new(
             RefCell::new(
-                Mempool::allocate(huge_page_id, mempool_size, 2048).unwrap()
+                Mempool::allocate(huge_page_id, mempool_size, 2048, ixgbe.numa_node).unwrap()
             )
         );
 
+        // one u32 mempool index per ring slot; reserved up front and pinned to the NIC's numa
+        // node like the ring and mempool above it, rather than left to float wherever the
+        // allocator's next heap page lands
+        let mut mempool_entries = Vec::with_capacity(NUM_RX_QUEUE_ENTRIES as usize);
+        bind_to_numa_node(
+            mempool_entries.as_mut_ptr() as *mut u8,
+            mempool_entries.capacity() * mem::size_of::<u32>(),
+            ixgbe.numa_node,
+        );
+
         let rx_queue = IxgbeRxQueue {
             descriptors: dma.virt as *mut ixgbe_adv_rx_desc,
             mempool,
             num_entries: NUM_RX_QUEUE_ENTRIES,
             rx_index: 0,
-            mempool_entries: Vec::new(),
+            mempool_entries,
+            interrupt_fd: None,
         };
 
         ixgbe.rx_queues.push(rx_queue);
@@ -183,10 +390,73 @@ fn init_rx(ixgbe: &mut IxgbeDevice, huge_page_id: &mut u32) {
         ixgbe.clear_flags32(IXGBE_DCA_RXCTRL(i), 1 << 12);
     }
 
+    // section 4.6.7.3 - spread incoming traffic across all configured rx queues
+    if ixgbe.num_rx_queues > 1 {
+        init_rss(ixgbe);
+    }
+
     // start rx
     ixgbe.set_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
 }
 
+// section 4.6.7.3 - programs the RSS hash key, the redirection table and the hash types in MRQC
+fn init_rss(ixgbe: &mut IxgbeDevice) {
+    write_rss_key(ixgbe);
+
+    for i in 0..ixgbe.reta.len() {
+        ixgbe.reta[i] = (i as u32 % ixgbe.num_rx_queues) as u8;
+    }
+    write_reta(ixgbe);
+
+    ixgbe.set_flags32(IXGBE_MRQC(), IXGBE_MRQC_RSSEN | IXGBE_MRQC_RSS_FIELD_IPV4 | IXGBE_MRQC_RSS_FIELD_IPV4_TCP | IXGBE_MRQC_RSS_FIELD_IPV6 | IXGBE_MRQC_RSS_FIELD_IPV6_TCP);
+}
+
+fn write_rss_key(ixgbe: &IxgbeDevice) {
+    for i in 0..(IXGBE_RSS_KEY_SIZE / 4) {
+        let word = (ixgbe.rss_key[i * 4] as u32)
+            | (ixgbe.rss_key[i * 4 + 1] as u32) << 8
+            | (ixgbe.rss_key[i * 4 + 2] as u32) << 16
+            | (ixgbe.rss_key[i * 4 + 3] as u32) << 24;
+        ixgbe.set_reg32(IXGBE_RSSRK(i as u32), word);
+    }
+}
+
+fn write_reta(ixgbe: &IxgbeDevice) {
+    for i in 0..(IXGBE_RETA_ENTRIES / 4) {
+        let word = (ixgbe.reta[i * 4] as u32)
+            | (ixgbe.reta[i * 4 + 1] as u32) << 8
+            | (ixgbe.reta[i * 4 + 2] as u32) << 16
+            | (ixgbe.reta[i * 4 + 3] as u32) << 24;
+        ixgbe.set_reg32(IXGBE_RETA(i as u32), word);
+    }
+}
+
+// Microsoft RSS Toeplitz hash, the same algorithm the 82599 hardware uses to pick a queue
+fn toeplitz_hash(key: &[u8; IXGBE_RSS_KEY_SIZE], data: &[u8]) -> u32 {
+    let mut result: u32 = 0;
+
+    for (byte_idx, &byte) in data.iter().enumerate() {
+        for bit in 0..8 {
+            if (byte & (0x80 >> bit)) == 0 {
+                continue;
+            }
+
+            let bit_offset = byte_idx * 8 + bit;
+            let mut window: u32 = 0;
+            for w in 0..32 {
+                let key_bit_offset = bit_offset + w;
+                let key_byte = key[key_bit_offset / 8];
+                let key_bit = (key_byte >> (7 - key_bit_offset % 8)) & 1;
+                window = (window << 1) | key_bit as u32;
+            }
+
+            result ^= window;
+        }
+    }
+
+    result
+}
+
 // section 4.6.8
 fn init_tx(ixgbe: &mut IxgbeDevice, huge_page_id: &mut u32) {
     // crc offload
@@ -208,7 +478,8 @@ fn init_tx(ixgbe: &mut IxgbeDevice, huge_page_id: &mut u32) {
         let ring_size_bytes = NUM_TX_QUEUE_ENTRIES * mem::size_of::<ixgbe_adv_tx_desc>() as u32;
 
         // TODO check result of allocate_dma_memory
-        let dma = DmaMemory::allocate(huge_page_id, ring_size_bytes).unwrap();
+        let dma = DmaMemory::allocate(huge_page_id, ring_size_bytes, ixgbe.numa_node).unwrap();
+        bind_to_numa_node(dma.virt, ring_size_bytes as usize, ixgbe.numa_node);
         unsafe { memset(dma.virt, ring_size_bytes, 0xff); }
 
         ixgbe.set_reg32(IXGBE_TDBAL(i), (dma.phys as u64 & 0xffffffff) as u32);
@@ -228,6 +499,10 @@ fn init_tx(ixgbe: &mut IxgbeDevice, huge_page_id: &mut u32) {
             num_entries: NUM_RX_QUEUE_ENTRIES,
             clean_index: 0,
             tx_index: 0,
+            ctx_cache: [None; IXGBE_CTXD_CACHE_SIZE],
+            ctx_curr: 0,
+            last_tdh: 0,
+            last_tdh_progress: Instant::now(),
         };
 
         ixgbe.tx_queues.push(tx_queue);
@@ -272,6 +547,13 @@ fn start_rx_queue(ixgbe: &mut IxgbeDevice, queue_id: u32, huge_page_id: &mut u32
     ixgbe.set_reg32(IXGBE_RDT(queue_id), queue.num_entries - 1);
 }
 
+// disables tx queue `queue_id` and waits for it to quiesce, section 4.6.7.1.2 - used to recover
+// a hung queue before resetting TDH/TDT and re-enabling it
+fn stop_tx_queue(ixgbe: &mut IxgbeDevice, queue_id: u32) {
+    ixgbe.clear_flags32(IXGBE_TXDCTL(queue_id), IXGBE_TXDCTL_ENABLE);
+    ixgbe.wait_clear_reg32(IXGBE_TXDCTL(queue_id), IXGBE_TXDCTL_ENABLE);
+}
+
 fn start_tx_queue(ixgbe: &mut IxgbeDevice, queue_id: u32) {
     {
         let queue = &mut ixgbe.tx_queues[queue_id as usize];
@@ -345,11 +627,50 @@ fn ixgbe_rx_batch(ixgbe: &mut IxgbeDevice, queue_id: u32, num_bufs: u32) -> Vec<
         ixgbe.rx_queues[queue_id as usize].rx_index = rx_index;
     }
 
-    thread::sleep(Duration::from_millis(100));
-
     packets
 }
 
+// writes an advanced context descriptor (section 7.2.3.2.4) at `index` for `offload`, tagged
+// with `ctx_idx` (0 or 1) so data descriptors can select it via their IDX bit
+unsafe fn write_ctx_descriptor(descriptors: *mut ixgbe_adv_tx_desc, index: u32, offload: &TxOffload, ctx_idx: usize) {
+    let vlan_macip_lens = ((offload.l2_len as u32) << IXGBE_ADVTXD_MACLEN_SHIFT) | (offload.l3_len as u32);
+
+    let mut mss_l4len_idx = (offload.l4_len as u32) << IXGBE_ADVTXD_L4LEN_SHIFT;
+    if offload.tso_mss > 0 {
+        mss_l4len_idx |= (offload.tso_mss as u32) << IXGBE_ADVTXD_MSS_SHIFT;
+    }
+    mss_l4len_idx |= (ctx_idx as u32) << IXGBE_ADVTXD_IDX_SHIFT;
+
+    // section 7.2.3.2.4: DTYP = ctxt with DEXT set, no other DCMD/status bits for a context descriptor
+    let type_tucmd_mlhl = IXGBE_ADVTXD_DCMD_DEXT | IXGBE_ADVTXD_DTYP_CTXT
+        | if offload.ipv4_checksum { IXGBE_ADVTXD_TUCMD_IPV4 } else { 0 }
+        | match offload.l4_checksum {
+            L4Checksum::None => 0,
+            L4Checksum::Tcp => IXGBE_ADVTXD_TUCMD_L4T_TCP,
+            L4Checksum::Udp => IXGBE_ADVTXD_TUCMD_L4T_UDP,
+            L4Checksum::Sctp => IXGBE_ADVTXD_TUCMD_L4T_SCTP,
+        };
+
+    ptr::write_volatile(descriptors.offset(index as isize) as *mut u32, vlan_macip_lens);
+    ptr::write_volatile((descriptors.offset(index as isize) as usize + mem::size_of::<u32>()) as *mut u32, 0); // seqnum_seed, unused
+    ptr::write_volatile((descriptors.offset(index as isize) as usize + 2 * mem::size_of::<u32>()) as *mut u32, type_tucmd_mlhl);
+    ptr::write_volatile((descriptors.offset(index as isize) as usize + 3 * mem::size_of::<u32>()) as *mut u32, mss_l4len_idx);
+}
+
+// finds a cached context matching `offload`, or picks the next slot to (re-)write; mirrors
+// what_advctx_update/ixgbe_set_xmit_ctx. Always returns the cache slot the caller must select
+// via the IDX bit, plus whether a new context descriptor needs to be emitted for it. On a miss,
+// the slot is only a proposal -- the caller commits it to `ctx_cache`/`ctx_curr` itself, after
+// actually writing the context descriptor, so a caller that bails out for lack of ring space
+// doesn't leave the cache claiming a context that was never written.
+fn what_advctx_update(queue: &IxgbeTxQueue, offload: &TxOffload) -> (usize, bool) {
+    if let Some(idx) = queue.ctx_cache.iter().position(|c| *c == Some(*offload)) {
+        return (idx, false); // already cached, no new context descriptor needed
+    }
+
+    (queue.ctx_curr, true)
+}
+
 fn ixgbe_tx_batch(ixgbe: &mut IxgbeDevice, queue_id: u32, packets: Vec<Packet>) -> u32 {
     let mut sent = 0;
 
@@ -391,24 +712,79 @@ fn ixgbe_tx_batch(ixgbe: &mut IxgbeDevice, queue_id: u32, packets: Vec<Packet>)
         queue.clean_index = clean_index;
 
         for packet in packets {
+            let offload = packet.offload();
+
+            // a non-default offload request is tagged with a context cache slot; a cache miss
+            // additionally needs its own context descriptor, which consumes a ring slot ahead
+            // of the data descriptor
+            let ctx_idx = if !offload.is_none() {
+                let (idx, needs_new_ctx) = what_advctx_update(queue, &offload);
+
+                if needs_new_ctx {
+                    let next_index = wrap_ring(cur_index, queue.num_entries);
+                    // the context descriptor and the data descriptor after it must both fit,
+                    // otherwise neither is written and the cache stays untouched
+                    if clean_index == next_index || clean_index == wrap_ring(next_index, queue.num_entries) {
+                        return sent as u32;
+                    }
+
+                    unsafe { write_ctx_descriptor(queue.descriptors, cur_index, &offload, idx); }
+                    queue.ctx_cache[idx] = Some(offload);
+                    queue.ctx_curr = (idx + 1) % IXGBE_CTXD_CACHE_SIZE;
+
+                    queue.queue.push_back(None);
+                    cur_index = next_index;
+                }
+
+                Some(idx)
+            } else {
+                None
+            };
+
             let next_index = wrap_ring(cur_index, queue.num_entries);
 
             if clean_index == next_index {
                 return sent as u32
             }
 
-            queue.tx_index = wrap_ring(queue.tx_index, queue.num_entries);
+            queue.tx_index = next_index;
+
+            let mut dcmd = IXGBE_ADVTXD_DCMD_EOP | IXGBE_ADVTXD_DCMD_RS | IXGBE_ADVTXD_DCMD_IFCS | IXGBE_ADVTXD_DCMD_DEXT | IXGBE_ADVTXD_DTYP_DATA | packet.len() as u32;
+            let mut olinfo = (packet.len() as u32) << IXGBE_ADVTXD_PAYLEN_SHIFT;
+
+            if !offload.is_none() {
+                if offload.ipv4_checksum {
+                    olinfo |= IXGBE_ADVTXD_POPTS_IXSM;
+                }
+                if offload.l4_checksum != L4Checksum::None {
+                    olinfo |= IXGBE_ADVTXD_POPTS_TXSM;
+                }
+                if offload.tso_mss > 0 {
+                    dcmd |= IXGBE_ADVTXD_DCMD_TSE;
+                    // paylen must be the unsegmented TCP payload length, not the full frame
+                    // length; header_len is caller-supplied and not derived from the packet,
+                    // so a bogus offload request must not underflow this subtraction
+                    let header_len = offload.l2_len as u32 + offload.l3_len as u32 + offload.l4_len as u32;
+                    let payload_len = (packet.len() as u32).checked_sub(header_len)
+                        .expect("offload header length exceeds packet length");
+                    olinfo = payload_len << IXGBE_ADVTXD_PAYLEN_SHIFT;
+                }
+
+                // select which of the two cached contexts this data descriptor applies;
+                // without this the NIC just reuses whichever context was written last
+                olinfo |= (ctx_idx.unwrap() as u32) << IXGBE_ADVTXD_IDX_SHIFT;
+            }
 
             unsafe {
                 // write to read.buffer_addr
                 ptr::write_volatile(queue.descriptors.offset(cur_index as isize) as *mut u64, virt_to_phys(packet.get_addr() as usize).unwrap() as u64);
-                // write to read.buffer_addr
-                ptr::write_volatile((queue.descriptors.offset(cur_index as isize) as usize + mem::size_of::<u64>()) as *mut u32, IXGBE_ADVTXD_DCMD_EOP | IXGBE_ADVTXD_DCMD_RS | IXGBE_ADVTXD_DCMD_IFCS | IXGBE_ADVTXD_DCMD_DEXT | IXGBE_ADVTXD_DTYP_DATA | packet.len() as u32);
+                // write to read.cmd_type_len
+                ptr::write_volatile((queue.descriptors.offset(cur_index as isize) as usize + mem::size_of::<u64>()) as *mut u32, dcmd);
                 // write to read.olinfo_status
-                ptr::write_volatile((queue.descriptors.offset(cur_index as isize) as usize + mem::size_of::<u64>() + mem::size_of::<u32>()) as *mut u32, (packet.len() as u32) << IXGBE_ADVTXD_PAYLEN_SHIFT);
+                ptr::write_volatile((queue.descriptors.offset(cur_index as isize) as usize + mem::size_of::<u64>() + mem::size_of::<u32>()) as *mut u32, olinfo);
             }
 
-            queue.queue.push_back(packet);
+            queue.queue.push_back(Some(packet));
 
             cur_index = next_index;
             sent = sent + 1;
@@ -430,9 +806,23 @@ impl IxyDriver for IxgbeDevice {
 
         let (addr, len) = pci_map(pci_addr)?;
 
+        let numa_node = read_numa_node(pci_addr);
+        println!("device is attached to numa node {}", numa_node);
+
         let rx_queues = Vec::new();
         let tx_queues = Vec::new();
-        let mut dev = IxgbeDevice { addr, len, num_rx_queues, num_tx_queues, rx_queues, tx_queues };
+        let mut dev = IxgbeDevice {
+            addr,
+            len,
+            num_rx_queues,
+            num_tx_queues,
+            rx_queues,
+            tx_queues,
+            rss_key: IXGBE_DEFAULT_RSS_KEY,
+            reta: [0; IXGBE_RETA_ENTRIES],
+            numa_node,
+            pci_addr: pci_addr.to_string(),
+        };
 
         reset_and_init(&mut dev);
 
@@ -457,17 +847,50 @@ impl IxyDriver for IxgbeDevice {
         let rx_bytes = self.get_reg32(IXGBE_GORCL) as u64 + ((self.get_reg32(IXGBE_GORCH) as u64) << 32);
         let tx_bytes = self.get_reg32(IXGBE_GOTCL) as u64 + ((self.get_reg32(IXGBE_GOTCH) as u64) << 32);
 
+        // RXMPC is one counter per traffic class; we don't use DCB/multiple TCs, so sum
+        // them all to get the total count of packets dropped for lack of rx descriptors
+        let rx_missed_pkts: u64 = (0..IXGBE_MAX_TRAFFIC_CLASSES)
+            .map(|tc| self.get_reg32(IXGBE_RXMPC(tc)) as u64)
+            .sum();
+
+        let crc_errors = self.get_reg32(IXGBE_CRCERRS) as u64;
+
         stats.rx_pkts += rx_pkts;
         stats.tx_pkts += tx_pkts;
         stats.rx_bytes += rx_bytes;
         stats.tx_bytes += tx_bytes;
+        stats.rx_missed_pkts += rx_missed_pkts;
+        stats.crc_errors += crc_errors;
+
+        for i in 0..self.num_rx_queues {
+            stats.rx_queue_pkts[i as usize] += self.get_reg32(IXGBE_QPRC(i)) as u64;
+        }
+        for i in 0..self.num_tx_queues {
+            stats.tx_queue_pkts[i as usize] += self.get_reg32(IXGBE_QPTC(i)) as u64;
+        }
     }
 
     fn reset_stats(&self) {
-        let rx_pkts = self.get_reg32(IXGBE_GPRC) as u64;
-        let tx_pkts = self.get_reg32(IXGBE_GPTC) as u64;
-        let rx_bytes = self.get_reg32(IXGBE_GORCL) as u64 + ((self.get_reg32(IXGBE_GORCH) as u64) << 32);
-        let tx_bytes = self.get_reg32(IXGBE_GOTCL) as u64 + ((self.get_reg32(IXGBE_GOTCH) as u64) << 32);
+        // all of the registers below are clear-on-read, so reading them here establishes a
+        // zero baseline for the first read_stats() call; the read values themselves are
+        // discarded on purpose
+        self.get_reg32(IXGBE_GPRC);
+        self.get_reg32(IXGBE_GPTC);
+        self.get_reg32(IXGBE_GORCL);
+        self.get_reg32(IXGBE_GORCH);
+        self.get_reg32(IXGBE_GOTCL);
+        self.get_reg32(IXGBE_GOTCH);
+        self.get_reg32(IXGBE_CRCERRS);
+
+        for tc in 0..IXGBE_MAX_TRAFFIC_CLASSES {
+            self.get_reg32(IXGBE_RXMPC(tc));
+        }
+        for i in 0..self.num_rx_queues {
+            self.get_reg32(IXGBE_QPRC(i));
+        }
+        for i in 0..self.num_tx_queues {
+            self.get_reg32(IXGBE_QPTC(i));
+        }
     }
 
     fn set_promisc(&self, enabled: bool) {
@@ -495,6 +918,129 @@ impl IxyDriver for IxgbeDevice {
 }
 
 impl IxgbeDevice {
+    /// Returns the NUMA node the device's PCI function is attached to, or `-1` if the host
+    /// doesn't report one. Applications pinning worker threads to queues should pin the thread
+    /// serving this device's queues to this node.
+    pub fn numa_node(&self) -> i32 {
+        self.numa_node
+    }
+
+    /// Switches queue `queue_id` from busy-polling to MSI-X interrupt mode: allocates a VFIO
+    /// vector for the queue, maps it via IVAR, sets the throttle rate in EITR, arms the queue's
+    /// cause for auto-mask-on-fire in EIAM and unmasks it in EIMS. After this call, drain the
+    /// queue with [`rx_batch_blocking`](Self::rx_batch_blocking) instead of `rx_batch`.
+    pub fn enable_rx_interrupt(&mut self, queue_id: u32, itr_interval_us: u32) -> io::Result<()> {
+        let fd = vfio_enable_msix_vector(&self.pci_addr, queue_id)?;
+
+        // section 7.3.2.2 - map rx queue `queue_id` to MSI-X vector `queue_id`; IVAR(n) holds
+        // queues 2n and 2n+1, rx cause in bits [7:0]/[23:16] and tx cause in bits [15:8]/[31:24]
+        let ivar_reg = IXGBE_IVAR(queue_id >> 1);
+        let lane_shift = 16 * (queue_id & 1);
+        let ivar = self.get_reg32(ivar_reg) & !(0xFF << lane_shift);
+        self.set_reg32(ivar_reg, ivar | ((queue_id | IXGBE_IVAR_ALLOC_VALID) << lane_shift));
+
+        // section 7.3.2.3 - interrupt throttle rate, in 2us units
+        self.set_reg32(IXGBE_EITR(queue_id), (itr_interval_us / 2) << 3);
+
+        self.set_flags32(IXGBE_GPIE, IXGBE_GPIE_MSIX_MODE | IXGBE_GPIE_PBA_SUPPORT | IXGBE_GPIE_OCD);
+        // auto-mask this cause in EIMS when it fires, so rx_batch_blocking's re-arm has
+        // something to undo
+        self.set_flags32(IXGBE_EIAM, 1 << queue_id);
+        self.set_reg32(IXGBE_EIMS, 1 << queue_id);
+
+        self.rx_queues[queue_id as usize].interrupt_fd = Some(fd);
+
+        Ok(())
+    }
+
+    /// Drains queue `queue_id` exactly like `rx_batch`, but first blocks on the queue's MSI-X
+    /// eventfd until the NIC signals new descriptors, instead of busy-polling. The queue must
+    /// have been switched into interrupt mode with `enable_rx_interrupt` first.
+    pub fn rx_batch_blocking(&mut self, queue_id: u32, num_packets: u32) -> Vec<Packet> {
+        let fd = self.rx_queues[queue_id as usize]
+            .interrupt_fd
+            .expect("queue is not in interrupt mode, call enable_rx_interrupt() first");
+
+        wait_for_interrupt(fd);
+
+        let packets = ixgbe_rx_batch(self, queue_id, num_packets);
+
+        // re-arm: EIAM auto-masks the cause on firing, so it must be explicitly unmasked again
+        self.set_reg32(IXGBE_EIMS, 1 << queue_id);
+
+        packets
+    }
+
+    /// Replaces the RSS hash key (RSSRK) with `key` and reprograms it on the NIC. Has no effect
+    /// if the device was initialized with a single rx queue, since RSS is disabled in that case.
+    pub fn set_rss_key(&mut self, key: [u8; IXGBE_RSS_KEY_SIZE]) {
+        self.rss_key = key;
+        if self.num_rx_queues > 1 {
+            write_rss_key(self);
+        }
+    }
+
+    /// Returns the rx queue a flow identified by `(src_ip, dst_ip, src_port, dst_port)` hashes
+    /// to, following the same Toeplitz hash and redirection table the NIC uses internally.
+    pub fn rss_queue_for_flow(&self, src_ip: u32, dst_ip: u32, src_port: u16, dst_port: u16) -> u32 {
+        let mut data = Vec::with_capacity(12);
+        data.extend_from_slice(&src_ip.to_be_bytes());
+        data.extend_from_slice(&dst_ip.to_be_bytes());
+        data.extend_from_slice(&src_port.to_be_bytes());
+        data.extend_from_slice(&dst_port.to_be_bytes());
+
+        let hash = toeplitz_hash(&self.rss_key, &data);
+        let reta_index = (hash as usize) % IXGBE_RETA_ENTRIES;
+
+        self.reta[reta_index] as u32
+    }
+
+    /// Checks whether tx queue `queue_id` has stopped making forward progress for longer than
+    /// `timeout`, and if so recovers it by re-running the queue init path. Mirrors
+    /// `ixgbe_check_tx_hang`/`ixgbe_tx_timeout_reset` in the Linux ixgbe driver. Returns `true`
+    /// if the queue was found hung (and has been recovered).
+    pub fn check_tx_hang(&mut self, queue_id: u32, timeout: Duration) -> bool {
+        let tdh = self.get_reg32(IXGBE_TDH(queue_id));
+        let tdt = self.get_reg32(IXGBE_TDT(queue_id));
+
+        let hung = {
+            let queue = &mut self.tx_queues[queue_id as usize];
+
+            if tdh != queue.last_tdh || tdh == tdt {
+                // either the head advanced since the last check, or there is nothing
+                // outstanding right now - both count as progress
+                queue.last_tdh = tdh;
+                queue.last_tdh_progress = Instant::now();
+                false
+            } else if queue.last_tdh_progress.elapsed() < timeout {
+                false
+            } else {
+                println!(
+                    "tx queue {} hung: tdh={:#x} tdt={:#x} clean_index={:#x} tx_index={:#x}",
+                    queue_id, tdh, tdt, queue.clean_index, queue.tx_index
+                );
+                true
+            }
+        };
+
+        if hung {
+            // disable→reset→re-enable, not a reset on a still-live queue that may be mid-DMA
+            stop_tx_queue(self, queue_id);
+            start_tx_queue(self, queue_id);
+
+            let queue = &mut self.tx_queues[queue_id as usize];
+            queue.clean_index = 0;
+            queue.tx_index = 0;
+            queue.queue.clear();
+            queue.ctx_cache = [None; IXGBE_CTXD_CACHE_SIZE];
+            queue.ctx_curr = 0;
+            queue.last_tdh = 0;
+            queue.last_tdh_progress = Instant::now();
+        }
+
+        hung
+    }
+
     fn get_reg32(&self, reg: u32) -> u32 {
         if reg as usize <= self.len - 4 as usize {
             unsafe { ptr::read_volatile((self.addr as usize + reg as usize) as *mut u32) }